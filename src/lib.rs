@@ -1,10 +1,9 @@
 #![feature(portable_simd)]
-#![feature(test)]
 
 use std::collections::VecDeque;
 use std::iter::Peekable;
-use std::ops::{Add, AddAssign, Div, Mul, Sub, SubAssign};
-use std::simd::i32x4;
+use std::ops::{Add, AddAssign, Div, Index, IndexMut, Mul, Sub, SubAssign};
+use std::simd::i64x4;
 
 #[cfg(test)]
 mod test;
@@ -34,11 +33,23 @@ impl<T: StackBlurrable, I: Iterator<Item = T>> StackBlur<T, I> {
 		self.ops
 	}
 
+	pub fn reset_with(&mut self, iter: I, radius: usize) {
+		self.iter = iter.peekable();
+		self.radius = radius;
+		self.done = true;
+	}
+
 	fn init(&mut self) {
 		self.done = false;
 
-		self.ops.clear();
-		self.ops.resize_with(self.radius * 2 + 2, T::default);
+		let need = self.radius * 2 + 2;
+		self.ops.truncate(need);
+		if self.ops.len() < need {
+			self.ops.resize_with(need, T::default);
+		}
+		for op in &mut self.ops {
+			*op = T::default();
+		}
 
 		self.sum = T::default();
 		self.rate = T::default();
@@ -113,11 +124,12 @@ impl<T: StackBlurrable, I: Iterator<Item = T>> Iterator for StackBlur<T, I> {
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
-struct ARGB(i32x4);
+#[allow(clippy::upper_case_acronyms)]
+struct ARGB(i64x4);
 
 impl ARGB {
 	fn from_argb(argb: u32) -> Self {
-		Self(i32x4::from_array(argb.to_be_bytes().map(|i| i as i32)))
+		Self(i64x4::from_array(argb.to_be_bytes().map(|i| i as i64)))
 	}
 
 	fn to_argb(self) -> u32 {
@@ -157,7 +169,7 @@ impl Mul<usize> for ARGB {
 	type Output = Self;
 
 	fn mul(self, rhs: usize) -> Self::Output {
-		Self(self.0 * i32x4::splat(rhs as i32))
+		Self(self.0 * i64x4::splat(rhs as i64))
 	}
 }
 
@@ -165,55 +177,477 @@ impl Div<usize> for ARGB {
 	type Output = Self;
 
 	fn div(self, rhs: usize) -> Self::Output {
-		Self(self.0 / i32x4::splat(rhs as i32))
+		Self(self.0 / i64x4::splat(rhs as i64))
 	}
 }
 
-pub fn blur_horiz(argb: &mut [u32], width: usize, height: usize, radius: usize) {
-	debug_assert_eq!(argb.len(), width * height);
+pub struct ImageMut<'a, T> {
+	data: &'a mut [T],
+	width: usize,
+	height: usize
+}
 
-	let mut ops = VecDeque::new();
+impl<'a, T> ImageMut<'a, T> {
+	pub fn new(data: &'a mut [T], width: usize, height: usize) -> Self {
+		assert_eq!(data.len(), width * height);
+		Self { data, width, height }
+	}
 
-	for row in argb.chunks_exact_mut(width) {
-		let not_safe = row as *mut [u32];
+	pub fn width(&self) -> usize {
+		self.width
+	}
 
-		let read = unsafe { (*not_safe).iter() }.copied().map(ARGB::from_argb);
+	pub fn height(&self) -> usize {
+		self.height
+	}
 
-		let mut iter = StackBlur::new(read, radius, ops);
+	pub fn row_iter_mut(&mut self) -> impl Iterator<Item = &mut [T]> {
+		self.data.chunks_exact_mut(self.width)
+	}
 
-		let mut index = 0usize;
-		while let Some(argb) = iter.next() {
-			unsafe { (*not_safe)[index] = argb.to_argb() };
-			index += 1;
-		}
+	pub fn col_iter(&self, col: usize) -> impl Iterator<Item = &T> {
+		assert!(col < self.width);
+		self.data.iter().skip(col).step_by(self.width)
+	}
 
-		ops = iter.into_ops();
+	pub fn col_iter_mut(&mut self, col: usize) -> impl Iterator<Item = &mut T> {
+		assert!(col < self.width);
+		self.data.iter_mut().skip(col).step_by(self.width)
 	}
 }
 
-pub fn blur_vert(argb: &mut [u32], width: usize, height: usize, radius: usize) {
-	debug_assert_eq!(argb.len(), width * height);
+impl<T> Index<usize> for ImageMut<'_, T> {
+	type Output = [T];
+
+	fn index(&self, row: usize) -> &Self::Output {
+		&self.data[row * self.width..][..self.width]
+	}
+}
+
+impl<T> IndexMut<usize> for ImageMut<'_, T> {
+	fn index_mut(&mut self, row: usize) -> &mut Self::Output {
+		&mut self.data[row * self.width..][..self.width]
+	}
+}
+
+pub fn blur_channels_horiz<T: StackBlurrable>(data: &mut [T], width: usize, height: usize, radius: usize) {
+	let mut img = ImageMut::new(data, width, height);
+
+	let mut iter = StackBlur::new(Vec::new().into_iter(), radius, VecDeque::new());
+
+	for row in img.row_iter_mut() {
+		let read = row.to_vec();
+
+		iter.reset_with(read.into_iter(), radius);
+
+		for (dst, px) in row.iter_mut().zip(iter.by_ref()) {
+			*dst = px;
+		}
+	}
+}
+
+pub fn blur_channels_vert<T: StackBlurrable>(data: &mut [T], width: usize, height: usize, radius: usize) {
+	let mut img = ImageMut::new(data, width, height);
 
-	let mut ops = VecDeque::new();
+	let mut iter = StackBlur::new(Vec::new().into_iter(), radius, VecDeque::new());
 
 	for col in 0..width {
-		let not_safe = argb as *mut [u32];
+		let read = img.col_iter(col).cloned().collect::<Vec<_>>();
+
+		iter.reset_with(read.into_iter(), radius);
+
+		for (dst, px) in img.col_iter_mut(col).zip(iter.by_ref()) {
+			*dst = px;
+		}
+	}
+}
+
+pub fn blur_channels<T: StackBlurrable>(data: &mut [T], width: usize, height: usize, radius: usize) {
+	blur_channels_horiz(data, width, height, radius);
+	blur_channels_vert(data, width, height, radius);
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Channels<const N: usize>(pub [i32; N]);
+
+impl<const N: usize> Default for Channels<N> {
+	fn default() -> Self {
+		Self([0; N])
+	}
+}
 
-		let read = unsafe { (*not_safe).iter() }.skip(col).step_by(width).copied().map(ARGB::from_argb);
+impl<const N: usize> Add for Channels<N> {
+	type Output = Self;
 
-		let mut iter = StackBlur::new(read, radius, ops);
+	fn add(mut self, rhs: Self) -> Self::Output {
+		for (a, b) in self.0.iter_mut().zip(rhs.0) {
+			*a += b;
+		}
+		self
+	}
+}
 
-		let mut index = col;
-		while let Some(argb) = iter.next() {
-			unsafe { (*not_safe)[index] = argb.to_argb() };
-			index += width;
+impl<const N: usize> Sub for Channels<N> {
+	type Output = Self;
+
+	fn sub(mut self, rhs: Self) -> Self::Output {
+		for (a, b) in self.0.iter_mut().zip(rhs.0) {
+			*a -= b;
 		}
+		self
+	}
+}
 
-		ops = iter.into_ops();
+impl<const N: usize> AddAssign for Channels<N> {
+	fn add_assign(&mut self, rhs: Self) {
+		*self = *self + rhs;
 	}
 }
 
+impl<const N: usize> SubAssign for Channels<N> {
+	fn sub_assign(&mut self, rhs: Self) {
+		*self = *self - rhs;
+	}
+}
+
+impl<const N: usize> Mul<usize> for Channels<N> {
+	type Output = Self;
+
+	fn mul(mut self, rhs: usize) -> Self::Output {
+		for a in &mut self.0 {
+			*a *= rhs as i32;
+		}
+		self
+	}
+}
+
+impl<const N: usize> Div<usize> for Channels<N> {
+	type Output = Self;
+
+	fn div(mut self, rhs: usize) -> Self::Output {
+		for a in &mut self.0 {
+			*a /= rhs as i32;
+		}
+		self
+	}
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ChannelsF32<const N: usize>(pub [f32; N]);
+
+impl<const N: usize> Default for ChannelsF32<N> {
+	fn default() -> Self {
+		Self([0.0; N])
+	}
+}
+
+impl<const N: usize> Add for ChannelsF32<N> {
+	type Output = Self;
+
+	fn add(mut self, rhs: Self) -> Self::Output {
+		for (a, b) in self.0.iter_mut().zip(rhs.0) {
+			*a += b;
+		}
+		self
+	}
+}
+
+impl<const N: usize> Sub for ChannelsF32<N> {
+	type Output = Self;
+
+	fn sub(mut self, rhs: Self) -> Self::Output {
+		for (a, b) in self.0.iter_mut().zip(rhs.0) {
+			*a -= b;
+		}
+		self
+	}
+}
+
+impl<const N: usize> AddAssign for ChannelsF32<N> {
+	fn add_assign(&mut self, rhs: Self) {
+		*self = *self + rhs;
+	}
+}
+
+impl<const N: usize> SubAssign for ChannelsF32<N> {
+	fn sub_assign(&mut self, rhs: Self) {
+		*self = *self - rhs;
+	}
+}
+
+impl<const N: usize> Mul<usize> for ChannelsF32<N> {
+	type Output = Self;
+
+	fn mul(mut self, rhs: usize) -> Self::Output {
+		for a in &mut self.0 {
+			*a *= rhs as f32;
+		}
+		self
+	}
+}
+
+impl<const N: usize> Div<usize> for ChannelsF32<N> {
+	type Output = Self;
+
+	fn div(mut self, rhs: usize) -> Self::Output {
+		for a in &mut self.0 {
+			*a /= rhs as f32;
+		}
+		self
+	}
+}
+
+fn blur_packed<const N: usize>(data: &mut [u8], width: usize, height: usize, radius: usize) {
+	let mut buf = data.chunks_exact(N).map(|chunk| {
+		let mut px = [0i32; N];
+		for (dst, src) in px.iter_mut().zip(chunk) {
+			*dst = *src as i32;
+		}
+		Channels(px)
+	}).collect::<Vec<_>>();
+
+	blur_channels(&mut buf, width, height, radius);
+
+	for (chunk, px) in data.chunks_exact_mut(N).zip(buf) {
+		for (dst, src) in chunk.iter_mut().zip(px.0) {
+			*dst = src.clamp(0, 255) as u8;
+		}
+	}
+}
+
+pub fn blur_rgba8(data: &mut [u8], width: usize, height: usize, radius: usize) {
+	blur_packed::<4>(data, width, height, radius);
+}
+
+pub fn blur_rgb8(data: &mut [u8], width: usize, height: usize, radius: usize) {
+	blur_packed::<3>(data, width, height, radius);
+}
+
+pub fn blur_luma8(data: &mut [u8], width: usize, height: usize, radius: usize) {
+	blur_packed::<1>(data, width, height, radius);
+}
+
+fn blur_packed_f32<const N: usize>(data: &mut [f32], width: usize, height: usize, radius: usize) {
+	let mut buf = data.chunks_exact(N).map(|chunk| {
+		let mut px = [0.0f32; N];
+		px.copy_from_slice(chunk);
+		ChannelsF32(px)
+	}).collect::<Vec<_>>();
+
+	blur_channels(&mut buf, width, height, radius);
+
+	for (chunk, px) in data.chunks_exact_mut(N).zip(buf) {
+		chunk.copy_from_slice(&px.0);
+	}
+}
+
+pub fn blur_rgba_f32(data: &mut [f32], width: usize, height: usize, radius: usize) {
+	blur_packed_f32::<4>(data, width, height, radius);
+}
+
+pub fn blur_rgb_f32(data: &mut [f32], width: usize, height: usize, radius: usize) {
+	blur_packed_f32::<3>(data, width, height, radius);
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct BlurOptions {
+	pub premultiply: bool,
+	pub linear_light: bool
+}
+
+// Linear-light intermediates need more than 8 bits of precision; channels are
+// kept at 12 bits (0..=4095). The `StackBlur` sum accumulates up to
+// `LINEAR_MAX * (radius + 1)^2`, which overflows `i32` around radius 724, so the
+// `ARGB` accumulator is `i64x4`, which stays exact up to a radius of about 47 million.
+const LINEAR_MAX: i32 = 4095;
+
+fn srgb_to_linear(c: f64) -> f64 {
+	if c <= 0.04045 {
+		c / 12.92
+	} else {
+		((c + 0.055) / 1.055).powf(2.4)
+	}
+}
+
+fn linear_to_srgb(l: f64) -> f64 {
+	if l <= 0.0031308 {
+		l * 12.92
+	} else {
+		1.055 * l.powf(1.0 / 2.4) - 0.055
+	}
+}
+
+struct SrgbLut {
+	opts: BlurOptions,
+	fwd: [i32; 256],
+	rev: [u8; LINEAR_MAX as usize + 1]
+}
+
+impl SrgbLut {
+	fn new(opts: BlurOptions) -> Self {
+		let mut fwd = [0i32; 256];
+		for (i, slot) in fwd.iter_mut().enumerate() {
+			*slot = (srgb_to_linear(i as f64 / 255.0) * LINEAR_MAX as f64).round() as i32;
+		}
+
+		let mut rev = [0u8; LINEAR_MAX as usize + 1];
+		for (l, slot) in rev.iter_mut().enumerate() {
+			*slot = (linear_to_srgb(l as f64 / LINEAR_MAX as f64) * 255.0).round().clamp(0.0, 255.0) as u8;
+		}
+
+		Self { opts, fwd, rev }
+	}
+
+	fn encode(&self, argb: u32) -> ARGB {
+		let [a, r, g, b] = argb.to_be_bytes();
+		let a = a as i64;
+
+		let mut chan = [r, g, b].map(|c| if self.opts.linear_light { self.fwd[c as usize] as i64 } else { c as i64 });
+
+		if self.opts.premultiply {
+			for c in &mut chan {
+				*c = *c * a / 255;
+			}
+		}
+
+		ARGB(i64x4::from_array([a, chan[0], chan[1], chan[2]]))
+	}
+
+	fn decode(&self, px: ARGB) -> u32 {
+		let [a, r, g, b] = px.0.to_array();
+		let a = a.clamp(0, 255);
+
+		let mut chan = [r, g, b];
+
+		if self.opts.premultiply {
+			for c in &mut chan {
+				*c = if a > 0 { *c * 255 / a } else { 0 };
+			}
+		}
+
+		let out = chan.map(|c| if self.opts.linear_light {
+			self.rev[c.clamp(0, LINEAR_MAX as i64) as usize]
+		} else {
+			c.clamp(0, 255) as u8
+		});
+
+		u32::from_be_bytes([a as u8, out[0], out[1], out[2]])
+	}
+}
+
+pub fn blur_horiz_with(argb: &mut [u32], width: usize, height: usize, radius: usize, opts: BlurOptions) {
+	if opts == BlurOptions::default() {
+		let mut buf = argb.iter().map(|&x| ARGB::from_argb(x)).collect::<Vec<_>>();
+		blur_channels_horiz(&mut buf, width, height, radius);
+		for (dst, px) in argb.iter_mut().zip(buf) {
+			*dst = px.to_argb();
+		}
+		return;
+	}
+
+	let lut = SrgbLut::new(opts);
+	let mut buf = argb.iter().map(|&x| lut.encode(x)).collect::<Vec<_>>();
+	blur_channels_horiz(&mut buf, width, height, radius);
+	for (dst, px) in argb.iter_mut().zip(buf) {
+		*dst = lut.decode(px);
+	}
+}
+
+pub fn blur_vert_with(argb: &mut [u32], width: usize, height: usize, radius: usize, opts: BlurOptions) {
+	if opts == BlurOptions::default() {
+		let mut buf = argb.iter().map(|&x| ARGB::from_argb(x)).collect::<Vec<_>>();
+		blur_channels_vert(&mut buf, width, height, radius);
+		for (dst, px) in argb.iter_mut().zip(buf) {
+			*dst = px.to_argb();
+		}
+		return;
+	}
+
+	let lut = SrgbLut::new(opts);
+	let mut buf = argb.iter().map(|&x| lut.encode(x)).collect::<Vec<_>>();
+	blur_channels_vert(&mut buf, width, height, radius);
+	for (dst, px) in argb.iter_mut().zip(buf) {
+		*dst = lut.decode(px);
+	}
+}
+
+pub fn blur_with(argb: &mut [u32], width: usize, height: usize, radius: usize, opts: BlurOptions) {
+	blur_horiz_with(argb, width, height, radius, opts);
+	blur_vert_with(argb, width, height, radius, opts);
+}
+
+pub fn blur_horiz(argb: &mut [u32], width: usize, height: usize, radius: usize) {
+	blur_horiz_with(argb, width, height, radius, BlurOptions::default());
+}
+
+pub fn blur_vert(argb: &mut [u32], width: usize, height: usize, radius: usize) {
+	blur_vert_with(argb, width, height, radius, BlurOptions::default());
+}
+
 pub fn blur(argb: &mut [u32], width: usize, height: usize, radius: usize) {
 	blur_horiz(argb, width, height, radius);
 	blur_vert(argb, width, height, radius);
 }
+
+#[cfg(feature = "image")]
+pub fn blur_image_rgba8(img: &mut image::RgbaImage, radius: usize) {
+	let (width, height) = img.dimensions();
+	blur_rgba8(img, width as usize, height as usize, radius);
+}
+
+#[cfg(feature = "image")]
+pub fn blur_image_rgb8(img: &mut image::RgbImage, radius: usize) {
+	let (width, height) = img.dimensions();
+	blur_rgb8(img, width as usize, height as usize, radius);
+}
+
+#[cfg(feature = "image")]
+pub fn blur_image_luma8(img: &mut image::GrayImage, radius: usize) {
+	let (width, height) = img.dimensions();
+	blur_luma8(img, width as usize, height as usize, radius);
+}
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+#[cfg(feature = "parallel")]
+pub fn par_blur_horiz(argb: &mut [u32], width: usize, height: usize, radius: usize) {
+	debug_assert_eq!(argb.len(), width * height);
+
+	argb.par_chunks_exact_mut(width).for_each(|row| {
+		let read = row.iter().copied().map(ARGB::from_argb).collect::<Vec<_>>();
+		let mut iter = StackBlur::new(read.into_iter(), radius, VecDeque::new());
+
+		for (dst, argb) in row.iter_mut().zip(iter.by_ref()) {
+			*dst = argb.to_argb();
+		}
+	});
+}
+
+#[cfg(feature = "parallel")]
+fn transpose(src: &[u32], dst: &mut [u32], width: usize, height: usize) {
+	debug_assert_eq!(src.len(), width * height);
+	debug_assert_eq!(dst.len(), width * height);
+
+	for y in 0..height {
+		for x in 0..width {
+			dst[x * height + y] = src[y * width + x];
+		}
+	}
+}
+
+#[cfg(feature = "parallel")]
+pub fn par_blur_vert(argb: &mut [u32], width: usize, height: usize, radius: usize) {
+	debug_assert_eq!(argb.len(), width * height);
+
+	let mut scratch = vec![0u32; width * height];
+	transpose(argb, &mut scratch, width, height);
+	par_blur_horiz(&mut scratch, height, width, radius);
+	transpose(&scratch, argb, height, width);
+}
+
+#[cfg(feature = "parallel")]
+pub fn par_blur(argb: &mut [u32], width: usize, height: usize, radius: usize) {
+	par_blur_horiz(argb, width, height, radius);
+	par_blur_vert(argb, width, height, radius);
+}
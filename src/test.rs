@@ -0,0 +1,58 @@
+use super::*;
+
+#[test]
+fn reset_with_matches_fresh() {
+	let data: Vec<Channels<1>> = (0..32).map(|i| Channels([i * 3 % 17])).collect();
+	let radius = 4;
+
+	let fresh = StackBlur::new(data.clone().into_iter(), radius, VecDeque::new()).collect::<Vec<_>>();
+
+	let mut reused = StackBlur::new(Vec::new().into_iter(), radius, VecDeque::new());
+	reused.reset_with(data.into_iter(), radius);
+	let reused = reused.collect::<Vec<_>>();
+
+	assert_eq!(fresh, reused);
+}
+
+#[test]
+fn premultiply_does_not_bleed_transparent_color() {
+	// Fully transparent green sitting between opaque red pixels must not tint
+	// its neighbours when premultiplied: its premultiplied RGB is zero.
+	const RED: u32 = 0xFFFF_0000;
+	const CLEAR_GREEN: u32 = 0x0000_FF00;
+
+	let mut argb = [RED, RED, CLEAR_GREEN, RED, RED];
+	let opts = BlurOptions { premultiply: true, linear_light: false };
+	blur_with(&mut argb, 5, 1, 2, opts);
+
+	for px in argb {
+		let [_, _, g, _] = px.to_be_bytes();
+		assert_eq!(g, 0, "transparent green bled into {px:08X}");
+	}
+
+	// Without premultiplication the straight-alpha average does pick up green.
+	let mut straight = [RED, RED, CLEAR_GREEN, RED, RED];
+	blur(&mut straight, 5, 1, 2);
+	assert!(straight.iter().any(|px| px.to_be_bytes()[2] > 0));
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn parallel_matches_serial() {
+	let (width, height, radius) = (8, 6, 3);
+	let base = (0..width * height)
+		.map(|i| (i as u32).wrapping_mul(2_654_435_761))
+		.collect::<Vec<_>>();
+
+	let mut serial = base.clone();
+	blur_horiz(&mut serial, width, height, radius);
+	let mut par = base.clone();
+	par_blur_horiz(&mut par, width, height, radius);
+	assert_eq!(serial, par);
+
+	let mut serial = base.clone();
+	blur(&mut serial, width, height, radius);
+	let mut par = base;
+	par_blur(&mut par, width, height, radius);
+	assert_eq!(serial, par);
+}